@@ -9,36 +9,352 @@ use crate::models::episode_downloads::EpisodeDownloads;
 use crate::models::{episode, podcast, EpisodeProgress};
 use crate::models::{Episode, Podcast};
 use crate::player::Player;
+use std::collections::HashMap;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Manager};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 use uuid::Uuid;
 
+#[derive(Clone)]
+pub struct DownloadQueue {
+    semaphore: Arc<Semaphore>,
+    active: Arc<AtomicUsize>,
+    max_concurrent: usize,
+    in_flight: Arc<Mutex<HashMap<i32, JoinHandle<()>>>>,
+}
+
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl DownloadQueue {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            active: Arc::new(AtomicUsize::new(0)),
+            max_concurrent,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn run<F>(&self, episode_id: i32, progress_indicator: &EpisodeDownloads, task: F)
+    where
+        F: std::future::Future<Output = AppResult<()>> + Send + 'static,
+    {
+        let semaphore = self.semaphore.clone();
+        let active = self.active.clone();
+        let in_flight = self.in_flight.clone();
+        let mut guard = in_flight.lock().unwrap();
+        if guard.contains_key(&episode_id) {
+            tracing::debug!(episode_id, "download already in flight, ignoring duplicate request");
+            return;
+        }
+
+        progress_indicator.set_queued(episode_id);
+        let in_flight_for_task = in_flight.clone();
+        let handle = tokio::spawn(
+            async move {
+                let started = std::time::Instant::now();
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                let _active_guard = ActiveGuard::new(active.clone());
+                if let Err(e) = task.await {
+                    tracing::error!("queued download failed: {}", e);
+                }
+                in_flight_for_task.lock().unwrap().remove(&episode_id);
+                tracing::debug!(episode_id, elapsed_ms = started.elapsed().as_millis() as u64, "download task finished");
+            }
+            .instrument(tracing::info_span!("download_episode_task", episode_id)),
+        );
+        guard.insert(episode_id, handle);
+    }
+
+    async fn cancel(&self, episode_id: i32) {
+        if let Some(handle) = self.in_flight.lock().unwrap().remove(&episode_id) {
+            handle.abort();
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct DownloadQueueStatus {
+    pub active: usize,
+    pub queued: usize,
+    pub max_concurrent: usize,
+}
+
+#[tauri::command]
+pub fn get_download_queue_status(queue: tauri::State<'_, DownloadQueue>) -> DownloadQueueStatus {
+    let active = queue.active.load(Ordering::SeqCst);
+    DownloadQueueStatus {
+        active,
+        queued: queue.in_flight.lock().unwrap().len().saturating_sub(active),
+        max_concurrent: queue.max_concurrent,
+    }
+}
+
+#[cfg(tokio_unstable)]
+pub fn build_instrumented_runtime(config: &Config) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if config.enable_runtime_diagnostics {
+        builder.enable_metrics_poll_count_histogram();
+    }
+    builder.build()
+}
+
+#[cfg(not(tokio_unstable))]
+pub fn build_instrumented_runtime(_config: &Config) -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()
+}
+
+#[derive(Clone, Default)]
+pub struct ImportTracker(Arc<Mutex<HashMap<String, std::time::Instant>>>);
+
+impl ImportTracker {
+    pub fn start(&self, import_id: &str) {
+        self.0.lock().unwrap().insert(import_id.to_string(), std::time::Instant::now());
+    }
+
+    pub fn finish(&self, import_id: &str) {
+        self.0.lock().unwrap().remove(import_id);
+    }
+
+    fn snapshot(&self) -> Vec<(String, std::time::Duration)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, started)| (id.clone(), started.elapsed()))
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct InFlightImport {
+    pub import_id: String,
+    pub elapsed_ms: u128,
+}
+
+#[derive(serde::Serialize)]
+pub struct RuntimeMetrics {
+    pub active_downloads: usize,
+    pub in_flight_imports: Vec<InFlightImport>,
+}
+
+#[tauri::command]
+pub fn get_runtime_metrics(
+    config_wrapper: tauri::State<'_, ConfigWrapper>,
+    queue: tauri::State<'_, DownloadQueue>,
+    import_tracker: tauri::State<'_, ImportTracker>,
+) -> RuntimeMetrics {
+    let enabled = config_wrapper.0.lock().unwrap().enable_runtime_diagnostics;
+    if !enabled {
+        return RuntimeMetrics {
+            active_downloads: 0,
+            in_flight_imports: Vec::new(),
+        };
+    }
+
+    RuntimeMetrics {
+        active_downloads: queue.active.load(Ordering::SeqCst),
+        in_flight_imports: import_tracker
+            .snapshot()
+            .into_iter()
+            .map(|(import_id, elapsed)| InFlightImport {
+                import_id,
+                elapsed_ms: elapsed.as_millis(),
+            })
+            .collect(),
+    }
+}
+
+#[tauri::command]
+pub async fn cancel_download(
+    id: i32,
+    queue: tauri::State<'_, DownloadQueue>,
+    progress_indicator: tauri::State<'_, EpisodeDownloads>,
+    app: AppHandle,
+) -> AppResult<()> {
+    queue.cancel(id).await;
+
+    let mut conn = db_connect();
+    let config = app.state::<ConfigWrapper>().0.lock().unwrap().clone();
+    if let Ok(episode_with_podcast) = episode::find_one_full(id, &mut conn) {
+        let download_path = PathBuf::from(&config.download_path).join(render_download_path(
+            &config.download_filename_template,
+            &episode_with_podcast.episode,
+            &episode_with_podcast.podcast,
+        ));
+        let _ = std::fs::remove_file(download_path);
+    }
+
+    progress_indicator.deref().clone().clear(id);
+    app.send_invalidate_cache(EntityChange::Episode(id))?;
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn list_all_podcasts() -> AppResult<Vec<Podcast>> {
     let mut connection = db_connect();
     podcast::list_all(&mut connection)
 }
 
+#[derive(Clone, serde::Serialize)]
+pub struct PodcastSyncReport {
+    pub podcast_id: i32,
+    pub podcast_title: String,
+    pub new_episode_count: usize,
+    pub errored: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct SyncReport {
+    pub podcasts: Vec<PodcastSyncReport>,
+    pub total_new_episodes: usize,
+}
+
 #[tauri::command]
 pub async fn sync_podcasts(app: AppHandle) -> AppResult<()> {
-    tokio::spawn(async move {
-        let mut connection = db_connect();
-        podcast::sync_podcasts(&mut connection).await.unwrap();
-        let podcasts = podcast::list_all(&mut connection).unwrap();
-
-        app.send_invalidate_cache(EntityChange::AllPodcasts).unwrap();
-        for podcast in &podcasts {
-            app.send_invalidate_cache(EntityChange::Podcast(podcast.id)).unwrap();
-            app.send_invalidate_cache(EntityChange::PodcastEpisodes(podcast.id))
-                .unwrap();
+    tokio::spawn(
+        async move {
+            let started = std::time::Instant::now();
+            let mut connection = db_connect();
+            let podcasts_before = podcast::list_all(&mut connection).unwrap();
+            let episode_ids_before: std::collections::HashMap<i32, std::collections::HashSet<i32>> = podcasts_before
+                .into_iter()
+                .map(|podcast| {
+                    let ids = episode::list_for_podcast(podcast.id, &mut connection)
+                        .map(|episodes| episodes.into_iter().map(|e| e.id).collect())
+                        .unwrap_or_default();
+                    (podcast.id, ids)
+                })
+                .collect();
+
+            let podcasts = podcast::list_all(&mut connection).unwrap();
+
+            app.send_invalidate_cache(EntityChange::AllPodcasts).unwrap();
+            let mut report = SyncReport {
+                podcasts: Vec::new(),
+                total_new_episodes: 0,
+            };
+            for podcast in &podcasts {
+                let errored = if let Err(e) = podcast::sync_one(podcast.id, &mut connection).await {
+                    tracing::warn!("failed to sync podcast {}: {}", podcast.id, e);
+                    true
+                } else {
+                    false
+                };
+
+                app.send_invalidate_cache(EntityChange::Podcast(podcast.id)).unwrap();
+                app.send_invalidate_cache(EntityChange::PodcastEpisodes(podcast.id))
+                    .unwrap();
+
+                let episodes_after = episode::list_for_podcast(podcast.id, &mut connection).unwrap_or_default();
+                let seen_before = episode_ids_before.get(&podcast.id);
+                let new_episodes: Vec<_> = episodes_after
+                    .into_iter()
+                    .filter(|e| seen_before.map_or(true, |ids| !ids.contains(&e.id)))
+                    .collect();
+                let new_episode_count = new_episodes.len();
+                if new_episode_count > 0 || errored {
+                    report.total_new_episodes += new_episode_count;
+                    report.podcasts.push(PodcastSyncReport {
+                        podcast_id: podcast.id,
+                        podcast_title: podcast.title.clone(),
+                        new_episode_count,
+                        errored,
+                    });
+                }
+
+                if !errored {
+                    apply_auto_download_policy(podcast, &new_episodes, &app).await;
+                }
+            }
+
+            let notify_on_new_episodes = app.state::<ConfigWrapper>().0.lock().unwrap().notify_on_new_episodes;
+            if notify_on_new_episodes && report.total_new_episodes > 0 {
+                notify_new_episodes(&report);
+            }
+            tracing::debug!(elapsed_ms = started.elapsed().as_millis() as u64, "sync task finished");
+            let _ = app.emit_all("sync-podcasts-done", report);
         }
-        let _ = app.emit_all("sync-podcasts-done", ());
-    });
+        .instrument(tracing::info_span!("sync_podcasts_task")),
+    );
 
     Ok(())
 }
 
+fn notify_new_episodes(report: &SyncReport) {
+    let podcasts_with_new_episodes: Vec<_> = report.podcasts.iter().filter(|p| p.new_episode_count > 0).collect();
+    let body = match podcasts_with_new_episodes.as_slice() {
+        [single] => format!("{} new episode(s) in {}", single.new_episode_count, single.podcast_title),
+        _ => format!(
+            "{} new episodes across {} podcasts",
+            report.total_new_episodes,
+            podcasts_with_new_episodes.len()
+        ),
+    };
+    if let Err(e) = tauri::api::notification::Notification::new("dev.dhbtk.dimppl")
+        .title("New episodes")
+        .body(body)
+        .show()
+    {
+        tracing::warn!("failed to show sync notification: {}", e);
+    }
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "mode", content = "value")]
+pub enum DownloadPolicy {
+    Never,
+    All,
+    LatestN(u32),
+}
+
+async fn apply_auto_download_policy(podcast: &Podcast, new_episodes: &[EpisodeWithProgress], app: &AppHandle) {
+    let mut candidates: Vec<&EpisodeWithProgress> = new_episodes.iter().filter(|e| !e.downloaded).collect();
+    let to_download: Vec<&EpisodeWithProgress> = match podcast.download_policy {
+        DownloadPolicy::Never => return,
+        DownloadPolicy::All => candidates,
+        DownloadPolicy::LatestN(n) => {
+            candidates.sort_unstable_by(|a, b| b.published_at.cmp(&a.published_at));
+            candidates.into_iter().take(n as usize).collect()
+        }
+    };
+    if to_download.is_empty() {
+        return;
+    }
+
+    let progress_indicator = app.state::<EpisodeDownloads>().deref().clone();
+    let queue = app.state::<DownloadQueue>().deref().clone();
+    for episode in to_download {
+        let task = do_download_episode(episode.id, progress_indicator.clone(), app.clone());
+        queue.run(episode.id, &progress_indicator, task).await;
+    }
+}
+
+#[tauri::command]
+pub async fn set_podcast_download_policy(id: i32, policy: DownloadPolicy) -> AppResult<()> {
+    let mut connection = db_connect();
+    podcast::set_download_policy(id, policy, &mut connection)
+}
+
 #[tauri::command]
 pub fn find_last_played() -> Option<EpisodeWithPodcast> {
     let mut connection = db_connect();
@@ -107,24 +423,122 @@ async fn do_import_podcast(url: String, app: AppHandle) -> AppResult<()> {
 }
 
 #[tauri::command]
-pub async fn import_podcast(url: String, app: AppHandle) -> AppResult<String> {
+pub async fn import_podcast(
+    url: String,
+    app: AppHandle,
+    import_tracker: tauri::State<'_, ImportTracker>,
+) -> AppResult<String> {
     let import_id = Uuid::new_v4().to_string();
     let import_id_clone = import_id.clone();
-    tokio::spawn(async move {
-        let result = do_import_podcast(url, app.clone()).await;
-        match result {
-            Ok(_) => {
-                let _ = app.emit_all("import-podcast-done", import_id_clone.clone());
-            }
-            Err(e) => {
-                let _ = app.emit_all("import-podcast-error", (import_id_clone.clone(), e.to_string()));
+    let import_tracker = import_tracker.deref().clone();
+    import_tracker.start(&import_id);
+    tokio::spawn(
+        async move {
+            let started = std::time::Instant::now();
+            let result = do_import_podcast(url, app.clone()).await;
+            match result {
+                Ok(_) => {
+                    let _ = app.emit_all("import-podcast-done", import_id_clone.clone());
+                }
+                Err(e) => {
+                    let _ = app.emit_all("import-podcast-error", (import_id_clone.clone(), e.to_string()));
+                }
             }
+            import_tracker.finish(&import_id_clone);
+            tracing::debug!(
+                import_id = %import_id_clone,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "import task finished"
+            );
         }
-    });
+        .instrument(tracing::info_span!("import_podcast_task", import_id = %import_id)),
+    );
 
     Ok(import_id)
 }
 
+#[derive(Clone, serde::Serialize)]
+pub struct OpmlImportReport {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[tauri::command]
+pub async fn export_podcasts_opml() -> AppResult<String> {
+    let mut conn = db_connect();
+    let podcasts = podcast::list_all(&mut conn)?;
+    Ok(build_opml(&podcasts))
+}
+
+#[tauri::command]
+pub async fn import_podcasts_opml(contents: String, app: AppHandle) -> AppResult<()> {
+    tokio::spawn(
+        async move {
+            let feed_urls = extract_opml_feed_urls(&contents);
+            let mut succeeded = 0;
+            let mut failed = 0;
+            for url in feed_urls {
+                match do_import_podcast(url.clone(), app.clone()).await {
+                    Ok(_) => succeeded += 1,
+                    Err(e) => {
+                        tracing::warn!("failed to import {} from OPML: {}", url, e);
+                        failed += 1;
+                    }
+                }
+            }
+            let _ = app.emit_all("import-opml-done", OpmlImportReport { succeeded, failed });
+        }
+        .instrument(tracing::info_span!("import_opml_task")),
+    );
+    Ok(())
+}
+
+fn extract_opml_feed_urls(opml: &str) -> Vec<String> {
+    let mut reader = quick_xml::Reader::from_str(opml);
+    reader.trim_text(true);
+    let mut urls = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(quick_xml::events::Event::Start(e)) | Ok(quick_xml::events::Event::Empty(e))
+                if e.name().as_ref() == b"outline" =>
+            {
+                for attr in e.attributes().flatten() {
+                    if attr.key.as_ref() == b"xmlUrl" {
+                        if let Ok(value) = attr.unescape_value() {
+                            urls.push(value.into_owned());
+                        }
+                    }
+                }
+            }
+            Ok(quick_xml::events::Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    urls
+}
+
+fn build_opml(podcasts: &[Podcast]) -> String {
+    let mut body = String::new();
+    for podcast in podcasts {
+        body.push_str(&format!(
+            "    <outline text=\"{title}\" title=\"{title}\" type=\"rss\" xmlUrl=\"{url}\"/>\n",
+            title = xml_escape(&podcast.title),
+            url = xml_escape(&podcast.feed_url),
+        ));
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n  <head>\n    <title>dimppl subscriptions</title>\n  </head>\n  <body>\n{body}  </body>\n</opml>\n"
+    )
+}
+
+fn xml_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 #[tauri::command]
 pub async fn list_podcast_episodes(id: i32) -> AppResult<Vec<EpisodeWithProgress>> {
     let mut conn = db_connect();
@@ -135,22 +549,69 @@ pub async fn list_podcast_episodes(id: i32) -> AppResult<Vec<EpisodeWithProgress
 pub async fn download_episode(
     id: i32,
     progress_indicator: tauri::State<'_, EpisodeDownloads>,
+    queue: tauri::State<'_, DownloadQueue>,
     app: AppHandle,
 ) -> AppResult<()> {
-    tokio::spawn(do_download_episode(id, progress_indicator.deref().clone(), app));
+    let progress_indicator = progress_indicator.deref().clone();
+    let task = do_download_episode(id, progress_indicator.clone(), app);
+    queue.run(id, &progress_indicator, task).await;
     Ok(())
 }
 
 async fn do_download_episode(id: i32, progress_indicator: EpisodeDownloads, app: AppHandle) -> AppResult<()> {
     let mut conn = db_connect();
+    let config = app.state::<ConfigWrapper>().0.lock().unwrap().clone();
+    let episode_with_podcast = episode::find_one_full(id, &mut conn)?;
+    let download_path = PathBuf::from(&config.download_path).join(render_download_path(
+        &config.download_filename_template,
+        &episode_with_podcast.episode,
+        &episode_with_podcast.podcast,
+    ));
+
     tracing::debug!("start_download");
-    episode::start_download(id, &progress_indicator, &mut conn).await?;
+    episode::start_download_to(id, &download_path, &progress_indicator, &mut conn).await?;
     tracing::debug!("start_download finished, now invalidate_cache");
     app.send_invalidate_cache(EntityChange::Episode(id))?;
     tracing::debug!("ok");
     Ok(())
 }
 
+const MAX_PATH_COMPONENT_LEN: usize = 120;
+
+fn sanitize_path_component(input: &str) -> String {
+    let replaced: String = input
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = replaced.trim_matches(|c: char| c == '.' || c == ' ');
+    let truncated: String = trimmed.chars().take(MAX_PATH_COMPONENT_LEN).collect();
+    if truncated.is_empty() {
+        "_".to_string()
+    } else {
+        truncated
+    }
+}
+
+fn render_download_path(template: &str, episode: &Episode, podcast: &Podcast) -> PathBuf {
+    let ext = episode
+        .enclosure_url
+        .rsplit('.')
+        .next()
+        .filter(|ext| ext.len() <= 5 && !ext.contains('/'))
+        .unwrap_or("mp3");
+    let rendered = template
+        .replace("{podcast}", &podcast.title)
+        .replace("{title}", &episode.title)
+        .replace("{pubdate}", &episode.published_at.format("%Y-%m-%d").to_string())
+        .replace("{ext}", ext);
+
+    rendered.split('/').map(sanitize_path_component).collect()
+}
+
 #[tauri::command]
 pub fn get_episode(id: i32) -> AppResult<Episode> {
     let mut conn = db_connect();
@@ -164,7 +625,11 @@ pub fn get_episode_full(id: i32) -> AppResult<EpisodeWithPodcast> {
 }
 
 #[tauri::command]
-pub fn play_episode(id: i32, player: tauri::State<'_, Arc<Player>>) -> AppResult<()> {
+pub fn play_episode(
+    id: i32,
+    player: tauri::State<'_, Arc<Player>>,
+    config_wrapper: tauri::State<'_, ConfigWrapper>,
+) -> AppResult<()> {
     let player = player.deref().clone();
     let mut conn = db_connect();
     let episode = episode::find_one(id, &mut conn)?;
@@ -174,7 +639,9 @@ pub fn play_episode(id: i32, player: tauri::State<'_, Arc<Player>>) -> AppResult
     } else {
         progress.listened_seconds as u64
     };
+    let playback_speed = config_wrapper.0.lock().unwrap().playback_speed;
     std::thread::spawn(move || {
+        player.set_playback_speed(playback_speed);
         let _ = player.play_episode(episode, start_seconds);
     });
     Ok(())
@@ -214,6 +681,23 @@ pub async fn set_volume(
     Ok(())
 }
 
+const MIN_PLAYBACK_SPEED: f32 = 0.5;
+const MAX_PLAYBACK_SPEED: f32 = 3.0;
+
+#[tauri::command]
+pub async fn set_playback_speed(
+    speed: f32,
+    config_wrapper: tauri::State<'_, ConfigWrapper>,
+    player: tauri::State<'_, Arc<Player>>,
+) -> AppResult<()> {
+    let speed = speed.clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED);
+    let mut config = config_wrapper.0.lock().unwrap().clone();
+    config.playback_speed = speed;
+    config_wrapper.update(config)?;
+    player.set_playback_speed(speed);
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn seek(to: i64, player: tauri::State<'_, Arc<Player>>) -> AppResult<()> {
     player.seek_to(to);
@@ -221,15 +705,20 @@ pub async fn seek(to: i64, player: tauri::State<'_, Arc<Player>>) -> AppResult<(
 }
 
 #[tauri::command]
-pub async fn set_up_media_controls(app: AppHandle, player: tauri::State<'_, Arc<Player>>) -> AppResult<()> {
+pub async fn set_up_media_controls(
+    app: AppHandle,
+    player: tauri::State<'_, Arc<Player>>,
+    config_wrapper: tauri::State<'_, ConfigWrapper>,
+) -> AppResult<()> {
     #[allow(unused)]
     if let Some(window) = app.get_window("main") {
         #[cfg(target_os = "windows")]
         let handle = Some(window.hwnd().unwrap().0 as *mut _);
         #[cfg(not(target_os = "windows"))]
         let handle = None;
+        let playback_speed = config_wrapper.0.lock().unwrap().playback_speed;
         tracing::debug!("setting up media controls");
-        player.set_up_media_controls(handle);
+        player.set_up_media_controls(handle, playback_speed);
     }
     Ok(())
 }